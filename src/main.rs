@@ -1,34 +1,39 @@
 use anyhow::{anyhow, Context, Result};
 use flate2::read::GzDecoder;
+use futures::stream::{self, StreamExt};
 use reqwest;
 use serde::Deserialize;
+use serde_json;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::fs::copy;
-use std::io::{Cursor, Seek, SeekFrom};
+use std::io::Write;
 use std::os::unix::fs;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use tar::Archive;
-use tempfile;
 use tokio;
 
 const CHROOT_DIR: &'static str = "/tmp/codecrafters";
 
-// Usage: your_docker.sh run <image> <command> <arg1> <arg2> ...
+// Usage: your_docker.sh run <image> [command] [arg1] [arg2] ...
 #[tokio::main]
 async fn main() -> Result<()> {
     // Parse args, auth, and pull image before filesystem and PID isolation
     let client = reqwest::Client::new();
     let args: Vec<_> = std::env::args().collect();
     let image = &args[2];
-    let command = &args[3];
-    let command_args = &args[4..];
+    let user_args = &args[3..];
 
     // Download and unpack target image into the newly created chroot directory
-    let (image_name, image_tag) = parse_image(image)?;
-    let token = auth(&client, image).await?;
-    let manifest = fetch_manifest(&client, &image_name, &image_tag, &token).await?;
-    let image_manifest = fetch_image_manifest(&client, &image_name, &token, &manifest).await?;
-    let _ = download_image_from_manifest(&client, &image_name, &token, &image_manifest).await?;
+    let image_ref = parse_image(image)?;
+    let platform = detect_platform();
+    let (manifest, token) = fetch_manifest(&client, &image_ref).await?;
+    let image_manifest =
+        fetch_image_manifest(&client, &image_ref, token.as_deref(), &platform, manifest).await?;
+    let container_config =
+        download_image_from_manifest(&client, &image_ref, token.as_deref(), &image_manifest)
+            .await?;
 
     // Create the chroot directory and the necessary child directories
     let _ = std::fs::create_dir_all(CHROOT_DIR).context("failed to create chroot directory")?;
@@ -52,17 +57,30 @@ async fn main() -> Result<()> {
         libc::unshare(libc::CLONE_NEWPID);
     };
 
-    let output = std::process::Command::new(command)
-        .args(command_args)
+    if let Some(working_dir) = &container_config.working_dir {
+        std::env::set_current_dir(working_dir)
+            .with_context(|| format!("failed to chdir into WorkingDir '{working_dir}'"))?;
+    }
+
+    let argv = resolve_argv(&container_config, user_args);
+    let (program, program_args) = argv
+        .split_first()
+        .ok_or_else(|| anyhow!("no command to run: image declares no Entrypoint or Cmd"))?;
+
+    let mut command = std::process::Command::new(program);
+    command.args(program_args);
+    command.env_clear();
+    for env in container_config.env.iter().flatten() {
+        if let Some((key, value)) = env.split_once('=') {
+            command.env(key, value);
+        }
+    }
+
+    let output = command
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
         .output()
-        .with_context(|| {
-            format!(
-                "Tried to run '{}' with arguments {:?}",
-                command, command_args
-            )
-        })?;
+        .with_context(|| format!("Tried to run '{}' with arguments {:?}", program, program_args))?;
 
     // Use child process exit code, fallback to 1
     let code = output.status.code().unwrap_or(1);
@@ -70,20 +88,107 @@ async fn main() -> Result<()> {
     std::process::exit(code);
 }
 
-/// Parse out the image name and tag.
+/// Build the final argv for the container: the image's `Entrypoint` (if any) followed by
+/// either the user-supplied command-line arguments, or the image's default `Cmd` when the
+/// user gave none. A user-supplied command overrides `Cmd` but the `Entrypoint` still runs
+/// first, unless the image itself declared an empty one.
+fn resolve_argv(config: &ContainerConfig, user_args: &[String]) -> Vec<String> {
+    let entrypoint = config.entrypoint.clone().unwrap_or_default();
+    let default_cmd = config.cmd.clone().unwrap_or_default();
+
+    let tail = if user_args.is_empty() {
+        default_cmd
+    } else {
+        user_args.to_vec()
+    };
+
+    let mut argv = entrypoint;
+    argv.extend(tail);
+    argv
+}
+
+/// A fully parsed image reference: `[registry/]repository[:tag|@digest]`.
 ///
-/// I am assuming we should always get something name:tag
-fn parse_image(image: &str) -> Result<(String, String)> {
-    let parsed_image_str: Vec<&str> = image.split(':').collect();
-    if parsed_image_str.len() == 1 {
-        return Ok((parsed_image_str[0].to_string(), "latest".to_string()));
-    }
-    if parsed_image_str.len() == 2 {
-        let (name, tag) = (parsed_image_str[0], parsed_image_str[1]);
-        return Ok((name.to_string(), tag.to_string()));
+/// `reference` holds either the tag or the `sha256:...` digest verbatim, since the registry
+/// API accepts both in the same `/manifests/{reference}` path segment.
+#[derive(Debug, Clone)]
+struct ImageReference {
+    pub registry: String,
+    pub repository: String,
+    pub reference: String,
+}
+
+/// Parse an image string into its registry host, repository path, and tag/digest.
+///
+/// Defaults the registry to Docker Hub (`registry-1.docker.io`) and, for Docker Hub only,
+/// injects the `library/` namespace when the repository has no explicit owner (e.g. `ubuntu`
+/// becomes `library/ubuntu`, but `bitnami/nginx` is left alone). A host segment is recognized
+/// by containing a `.` or `:` (a domain or `host:port`) or being literally `localhost`, which
+/// lets `ghcr.io/owner/repo` and `localhost:5000/repo` resolve to other registries entirely.
+fn parse_image(image: &str) -> Result<ImageReference> {
+    let (before_reference, reference) = match image.rsplit_once('@') {
+        Some((name, digest)) => (name, digest.to_string()),
+        None => match image.rsplit_once(':') {
+            // A ':' after the last '/' is a tag; a ':' before it (e.g. "host:port/repo") is not.
+            Some((name, tag)) if !tag.contains('/') => (name, tag.to_string()),
+            _ => (image, "latest".to_string()),
+        },
+    };
+
+    let looks_like_host = |segment: &str| {
+        segment == "localhost" || segment.contains('.') || segment.contains(':')
+    };
+
+    let (registry, repository) = match before_reference.split_once('/') {
+        Some((host, rest)) if looks_like_host(host) => (host.to_string(), rest.to_string()),
+        _ => (
+            "registry-1.docker.io".to_string(),
+            before_reference.to_string(),
+        ),
+    };
+
+    let repository = if registry == "registry-1.docker.io" && !repository.contains('/') {
+        format!("library/{repository}")
+    } else {
+        repository
+    };
+
+    Ok(ImageReference {
+        registry,
+        repository,
+        reference,
+    })
+}
+
+/// The OCI platform we're pulling an image for: `architecture`/`os` as used in manifest
+/// platform objects (e.g. `amd64`/`linux`), plus an optional `variant` (e.g. `v7` for arm).
+#[derive(Debug, Clone)]
+struct Platform {
+    pub architecture: String,
+    pub os: String,
+    pub variant: Option<String>,
+}
+
+/// Detect the platform we're running on and map it to OCI naming, since manifest indexes
+/// key their entries on OCI architecture/os names rather than Rust's `std::env::consts`.
+fn detect_platform() -> Platform {
+    let architecture = match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        other => other,
     }
+    .to_string();
 
-    Err(anyhow!("Unexpected image name"))
+    let variant = match std::env::consts::ARCH {
+        "arm" => Some("v7".to_string()),
+        _ => None,
+    };
+
+    Platform {
+        architecture,
+        os: std::env::consts::OS.to_string(),
+        variant,
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -94,10 +199,20 @@ struct TokenResponse {
     pub issued_at: String,
 }
 
+/// The response from the initial `/manifests/{reference}` request, which is either a fat
+/// manifest (image index) listing one sub-manifest per platform, or, for images published
+/// without an index, a direct [`ImageManifest`] for the only platform that exists.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ManifestResponse {
+    Index(ManifestIndex),
+    Image(ImageManifest),
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 #[allow(dead_code)]
-struct ManifestResponse {
+struct ManifestIndex {
     pub manifests: Vec<Manifest>,
     pub media_type: String,
     pub schema_version: u8,
@@ -119,6 +234,7 @@ struct Manifest {
 struct ManifestPlatform {
     pub architecture: String,
     pub os: String,
+    pub variant: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -161,57 +277,133 @@ struct ImageSubject {
     pub size: u32,
 }
 
-/// Fetch an auth token for our image, with only the pull scope
-async fn auth(client: &reqwest::Client, image: &str) -> Result<String> {
-    let (image_name, _) = parse_image(image).unwrap();
-    let request = format!(
-        "https://auth.docker.io/token?service=registry.docker.io&scope=repository:library/{image_name}:pull",
-    );
-    let response: TokenResponse = client
-        .get(request)
+/// The config blob referenced by `ImageConfig.digest`. Only its `config` object is interesting
+/// to us; the rest (`rootfs`, `history`, ...) we don't need.
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+struct ConfigBlob {
+    pub config: ContainerConfig,
+}
+
+/// The container defaults declared by the image, in the legacy Docker field naming the
+/// registry still serves (`Env`, `Entrypoint`, `Cmd`, `WorkingDir`, all PascalCase).
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(rename_all = "PascalCase")]
+#[allow(dead_code)]
+struct ContainerConfig {
+    pub env: Option<Vec<String>>,
+    pub entrypoint: Option<Vec<String>>,
+    pub cmd: Option<Vec<String>>,
+    pub working_dir: Option<String>,
+}
+
+/// The `Bearer realm="...",service="...",scope="..."` challenge a registry sends back on an
+/// unauthenticated request, per the [distribution auth spec](https://distribution.github.io/distribution/spec/auth/token/).
+struct BearerChallenge {
+    pub realm: String,
+    pub service: Option<String>,
+    pub scope: Option<String>,
+}
+
+/// Parse a `WWW-Authenticate` header value into its `realm`/`service`/`scope` fields.
+fn parse_bearer_challenge(header: &str) -> Result<BearerChallenge> {
+    let fields = header
+        .strip_prefix("Bearer ")
+        .ok_or_else(|| anyhow!("unsupported auth challenge '{header}'"))?;
+
+    let mut realm = None;
+    let mut service = None;
+    let mut scope = None;
+
+    for field in fields.split(',') {
+        let (key, value) = field
+            .split_once('=')
+            .ok_or_else(|| anyhow!("malformed auth challenge field '{field}'"))?;
+        let value = value.trim().trim_matches('"').to_string();
+        match key.trim() {
+            "realm" => realm = Some(value),
+            "service" => service = Some(value),
+            "scope" => scope = Some(value),
+            _ => {}
+        }
+    }
+
+    Ok(BearerChallenge {
+        realm: realm.ok_or_else(|| anyhow!("auth challenge for '{header}' is missing realm"))?,
+        service,
+        scope,
+    })
+}
+
+/// Fetch an auth token for the given challenge, with only the pull scope it asked for.
+async fn fetch_token(client: &reqwest::Client, challenge: &BearerChallenge) -> Result<String> {
+    let mut token_request = client.get(&challenge.realm);
+    if let Some(service) = &challenge.service {
+        token_request = token_request.query(&[("service", service)]);
+    }
+    if let Some(scope) = &challenge.scope {
+        token_request = token_request.query(&[("scope", scope)]);
+    }
+
+    let response: TokenResponse = token_request
         .send()
         .await
-        .context("failed to send request")?
+        .context("failed to send token request")?
         .json()
         .await
         .context("failed to deserialize json response")?;
     Ok(response.token)
 }
 
-/// We need to initially fetch the manifests associated with the target image. This will return
-/// a [`ManifestResponse`], which contains info about which digests are associated with
-/// which platform specific images (i.e. linux/amd64, linux/arm, ...)
+/// `Accept` header sent with manifest requests: Docker's media types alongside their OCI
+/// equivalents, since registries only return the types we advertise we understand.
+const MANIFEST_ACCEPT: &str = "application/vnd.docker.distribution.manifest.v2+json, application/vnd.docker.distribution.manifest.list.v2+json, application/vnd.oci.image.manifest.v1+json, application/vnd.oci.image.index.v1+json";
+
+/// Fetch the manifests associated with the target image, returning the parsed
+/// [`ManifestResponse`] alongside the pull token if the registry required one.
+///
+/// We try unauthenticated first: a registry that requires auth responds `401` with a
+/// `WWW-Authenticate` challenge telling us where to get a token and for which service/scope, so
+/// the same code works against Docker Hub, GHCR, Quay, or a private registry. A registry that
+/// doesn't require auth (e.g. a local one) answers the very first request, so we only pay for a
+/// second round-trip when a token is actually needed.
 async fn fetch_manifest(
     client: &reqwest::Client,
-    image_name: &str,
-    image_tag: &str,
-    token: &str,
-) -> Result<ManifestResponse> {
-    let request =
-        format!("https://registry.hub.docker.com/v2/library/{image_name}/manifests/{image_tag}",);
+    image_ref: &ImageReference,
+) -> Result<(ManifestResponse, Option<String>)> {
+    let request = format!(
+        "https://{}/v2/{}/manifests/{}",
+        image_ref.registry, image_ref.repository, image_ref.reference
+    );
 
     let response = client
         .get(&request)
-        .bearer_auth(token)
-        .header(
-            "Accept",
-            "application/vnd.docker.distribution.manifest.v2+json",
-        )
+        .header("Accept", MANIFEST_ACCEPT)
         .send()
         .await
-        .context("failed to fetch manifest")?
-        .text()
-        .await;
+        .context("failed to fetch manifest")?;
 
-    println!("Response: {:?}", response);
+    if response.status() != reqwest::StatusCode::UNAUTHORIZED {
+        let manifest: ManifestResponse = response
+            .json()
+            .await
+            .context("failed to deserialize manifest")?;
+        return Ok((manifest, None));
+    }
 
-    let response: ManifestResponse = client
-        .get(request)
-        .bearer_auth(token)
-        .header(
-            "Accept",
-            "application/vnd.docker.distribution.manifest.v2+json",
-        )
+    let challenge_header = response
+        .headers()
+        .get(reqwest::header::WWW_AUTHENTICATE)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| anyhow!("registry demanded auth but sent no WWW-Authenticate header"))?
+        .to_string();
+    let challenge = parse_bearer_challenge(&challenge_header)?;
+    let token = fetch_token(client, &challenge).await?;
+
+    let manifest: ManifestResponse = client
+        .get(&request)
+        .header("Accept", MANIFEST_ACCEPT)
+        .bearer_auth(&token)
         .send()
         .await
         .context("failed to fetch manifest")?
@@ -219,7 +411,7 @@ async fn fetch_manifest(
         .await
         .context("failed to deserialize manifest")?;
 
-    Ok(response)
+    Ok((manifest, Some(token)))
 }
 
 /// After fetching all the image manifests for a particular image, we need to hit the same endpoint
@@ -234,14 +426,29 @@ async fn fetch_manifest(
 /// This will produce our [`ImageManifest`], which contains the information about our layers that we want
 async fn fetch_image_manifest(
     client: &reqwest::Client,
-    image_name: &str,
-    token: &str,
-    manifest: &ManifestResponse,
+    image_ref: &ImageReference,
+    token: Option<&str>,
+    platform: &Platform,
+    manifest: ManifestResponse,
 ) -> Result<ImageManifest> {
-    let target_images: Vec<(String, String)> = manifest
+    // Some registries skip the fat manifest entirely and hand back a single image manifest
+    // straight away; there's nothing left to pick, so just use it.
+    let index = match manifest {
+        ManifestResponse::Image(image_manifest) => return Ok(image_manifest),
+        ManifestResponse::Index(index) => index,
+    };
+
+    let target_images: Vec<(String, String)> = index
         .manifests
         .iter()
-        .filter(|m| m.platform.architecture == "amd64" && m.platform.os == "linux")
+        .filter(|m| {
+            m.platform.architecture == platform.architecture
+                && m.platform.os == platform.os
+                // A `None` requested variant means "any" rather than "must be unset" — we
+                // don't always know the exact variant an arm64/arm host needs, but indexes
+                // often list one (e.g. arm64 as `v8`) anyway.
+                && (platform.variant.is_none() || m.platform.variant == platform.variant)
+        })
         .map(|m| {
             let digest = m.digest.clone();
             let media_type = m.media_type.clone();
@@ -249,64 +456,453 @@ async fn fetch_image_manifest(
         })
         .collect();
 
-    for (digest, media_type) in target_images.iter() {
-        let request =
-            format!("https://registry.hub.docker.com/v2/library/{image_name}/manifests/{digest}",);
+    // We only care about the first matching digest.
+    let (digest, media_type) = target_images
+        .first()
+        .ok_or_else(|| anyhow!("Failed to get platform image digest"))?;
 
-        let response: ImageManifest = client
-            .get(request)
-            .bearer_auth(token)
-            .header("Accept", media_type)
-            .send()
-            .await
-            .context("failed to fetch manifest")?
-            .json()
-            .await
-            .context("failed to deserialize manifest")?;
+    let request = format!(
+        "https://{}/v2/{}/manifests/{digest}",
+        image_ref.registry, image_ref.repository
+    );
 
-        // We only care about first digest
-        return Ok(response);
+    let mut builder = client.get(request).header("Accept", media_type);
+    if let Some(token) = token {
+        builder = builder.bearer_auth(token);
     }
+    let response: ImageManifest = builder
+        .send()
+        .await
+        .context("failed to fetch manifest")?
+        .json()
+        .await
+        .context("failed to deserialize manifest")?;
 
-    return Err(anyhow!("Failed to get platform image digest"));
+    Ok(response)
 }
 
+/// Compare an already-computed hex digest against the algo:hex `digest` a registry gave us,
+/// bailing out if they don't match.
+///
+/// Only `sha256` is supported today, which is all the registries we talk to ever send.
+fn verify_digest_hex(actual_hex: &str, digest: &str) -> Result<()> {
+    let (algo, expected_hex) = digest
+        .split_once(':')
+        .ok_or_else(|| anyhow!("malformed digest '{digest}', expected 'algo:hex'"))?;
+
+    if algo != "sha256" {
+        return Err(anyhow!("unsupported digest algorithm '{algo}'"));
+    }
+
+    if actual_hex != expected_hex {
+        return Err(anyhow!(
+            "digest mismatch for {digest}: got sha256:{actual_hex}"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Extract a single layer tarball into `chroot_dir`, honoring OCI/AUFS whiteout markers instead
+/// of just unpacking everything with [`Archive::unpack`].
+///
+/// A `.wh.<name>` entry means this layer deletes `<name>`, which must be removed from the tree
+/// extracted so far from lower layers. A `.wh..wh..opq` entry marks its containing directory opaque, meaning
+/// everything already extracted into that directory from lower layers must be cleared before
+/// this layer's own entries for it are applied. Neither marker itself is written to disk.
+///
+/// Whiteouts only ever apply to content from *lower* layers, never to this layer's own entries,
+/// but a tarball doesn't guarantee its whiteout markers come before the regular entries they'd
+/// otherwise shadow. So we make two passes over `layer_path`: first apply every whiteout this
+/// layer declares, then extract its regular entries on top, rather than interleaving the two in
+/// whatever order the archive happens to store them.
+fn apply_layer(layer_path: &Path, chroot_dir: &str) -> Result<()> {
+    for entry in open_layer_archive(layer_path)?
+        .entries()
+        .context("failed to read archive entries")?
+    {
+        let entry = entry.context("failed to read archive entry")?;
+        let path = entry.path().context("failed to read entry path")?.into_owned();
+
+        let file_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("archive entry has no file name: {}", path.display()))?;
+
+        if file_name == ".wh..wh..opq" {
+            let dir = chroot_dir_join(chroot_dir, path.parent())?;
+            if dir.is_dir() {
+                std::fs::remove_dir_all(&dir).with_context(|| {
+                    format!("failed to clear opaque directory '{}'", dir.display())
+                })?;
+                std::fs::create_dir_all(&dir).with_context(|| {
+                    format!("failed to recreate opaque directory '{}'", dir.display())
+                })?;
+            }
+        } else if let Some(deleted_name) = file_name.strip_prefix(".wh.") {
+            let target = chroot_dir_join(chroot_dir, path.parent())?.join(deleted_name);
+            if let Ok(metadata) = std::fs::symlink_metadata(&target) {
+                if metadata.is_dir() {
+                    std::fs::remove_dir_all(&target).with_context(|| {
+                        format!("failed to remove whited-out directory '{}'", target.display())
+                    })?;
+                } else {
+                    std::fs::remove_file(&target).with_context(|| {
+                        format!("failed to remove whited-out file '{}'", target.display())
+                    })?;
+                }
+            }
+        }
+    }
+
+    for entry in open_layer_archive(layer_path)?
+        .entries()
+        .context("failed to read archive entries")?
+    {
+        let mut entry = entry.context("failed to read archive entry")?;
+        let path = entry.path().context("failed to read entry path")?.into_owned();
+
+        let file_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("archive entry has no file name: {}", path.display()))?;
+
+        if file_name.starts_with(".wh.") {
+            continue;
+        }
+
+        entry
+            .unpack_in(chroot_dir)
+            .with_context(|| format!("failed to unpack entry '{}'", path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Open a gzip-compressed layer tarball fresh, for the two independent passes [`apply_layer`]
+/// makes over it.
+fn open_layer_archive(layer_path: &Path) -> Result<Archive<GzDecoder<std::fs::File>>> {
+    let file = std::fs::File::open(layer_path)
+        .with_context(|| format!("failed to open layer tarball '{}'", layer_path.display()))?;
+    Ok(Archive::new(GzDecoder::new(file)))
+}
+
+/// Join an optional entry-relative directory onto the chroot root, rejecting any path that
+/// would escape it.
+///
+/// Unlike [`tar::Entry::unpack_in`], which sanitizes paths for us, whiteout handling builds its
+/// own target paths by hand, so a malicious `..` component or an absolute path in a tar entry
+/// (e.g. `../../etc/.wh.passwd`) could otherwise reach outside `chroot_dir` entirely.
+fn chroot_dir_join(chroot_dir: &str, dir: Option<&Path>) -> Result<PathBuf> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => return Ok(Path::new(chroot_dir).to_path_buf()),
+    };
+
+    if dir.components().any(|component| {
+        !matches!(
+            component,
+            std::path::Component::Normal(_) | std::path::Component::CurDir
+        )
+    }) {
+        return Err(anyhow!(
+            "archive entry path escapes the chroot directory: {}",
+            dir.display()
+        ));
+    }
+
+    Ok(Path::new(chroot_dir).join(dir))
+}
+
+/// Where verified blobs are cached on disk, keyed by their `sha256:...` digest so re-pulling an
+/// image, or pulling a different image that shares a base layer, skips the network entirely.
+const BLOB_CACHE_DIR: &str = "/tmp/codecrafters-blob-cache";
+
+/// Map a verified digest to its path in [`BLOB_CACHE_DIR`], replacing `:` since not every
+/// filesystem we might run on tolerates it in a file name.
+fn blob_cache_path(digest: &str) -> PathBuf {
+    Path::new(BLOB_CACHE_DIR).join(digest.replace(':', "_"))
+}
+
+/// Download a single blob (layer or config), streaming it straight to disk while hashing it
+/// inline instead of buffering the whole thing in memory, and cache it under its verified
+/// digest. If the digest is already cached, skip the network entirely.
+async fn fetch_blob_cached(
+    client: &reqwest::Client,
+    image_ref: &ImageReference,
+    token: Option<&str>,
+    digest: &str,
+    media_type: &str,
+) -> Result<PathBuf> {
+    let cached_path = blob_cache_path(digest);
+    if cached_path.exists() {
+        return Ok(cached_path);
+    }
+
+    std::fs::create_dir_all(BLOB_CACHE_DIR).context("failed to create blob cache directory")?;
+
+    let request = format!(
+        "https://{}/v2/{}/blobs/{digest}",
+        image_ref.registry, image_ref.repository
+    );
+    let mut builder = client.get(request).header(reqwest::header::ACCEPT, media_type);
+    if let Some(token) = token {
+        builder = builder.bearer_auth(token);
+    }
+    let response = builder
+        .send()
+        .await
+        .with_context(|| format!("failed to download blob {digest}"))?;
+
+    // Write to a temp path in the cache dir and only rename it into place once the digest has
+    // been verified, so a crash or mismatch never leaves a half-written blob under its real name.
+    let tmp_path = cached_path.with_extension("part");
+    let mut file =
+        std::fs::File::create(&tmp_path).context("failed to create blob cache tempfile")?;
+    let mut hasher = Sha256::new();
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.with_context(|| format!("failed to stream blob {digest}"))?;
+        hasher.update(&chunk);
+        file.write_all(&chunk)
+            .with_context(|| format!("failed to write blob {digest} to cache"))?;
+    }
+
+    let actual_hex = format!("{:x}", hasher.finalize());
+    if let Err(err) = verify_digest_hex(&actual_hex, digest) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+
+    std::fs::rename(&tmp_path, &cached_path)
+        .with_context(|| format!("failed to move blob {digest} into cache"))?;
+    Ok(cached_path)
+}
+
+/// How many layers to download at once. Unpacking still happens strictly in manifest order
+/// afterwards, so this only parallelizes the network fetch.
+const MAX_CONCURRENT_LAYER_DOWNLOADS: usize = 4;
+
 /// Now that we have our image manifest for our platform, we can download and unpack the image
 /// to our chroot'ed directory
 async fn download_image_from_manifest(
     client: &reqwest::Client,
-    image_name: &str,
-    token: &str,
+    image_ref: &ImageReference,
+    token: Option<&str>,
     manifest: &ImageManifest,
-) -> Result<()> {
-    for layer in manifest.layers.iter() {
-        let request = format!(
-            "https://registry.hub.docker.com/v2/library/{image_name}/blobs/{}",
-            &layer.digest
+) -> Result<ContainerConfig> {
+    let config_path = fetch_blob_cached(
+        client,
+        image_ref,
+        token,
+        &manifest.config.digest,
+        &manifest.config.media_type,
+    )
+    .await?;
+    let config_bytes =
+        std::fs::read(&config_path).context("failed to read cached image config")?;
+    let config_blob: ConfigBlob =
+        serde_json::from_slice(&config_bytes).context("failed to deserialize image config")?;
+
+    // Dedupe by digest before fanning out: some images repeat the same layer digest more than
+    // once, and fetching it concurrently from two tasks would race them both writing the same
+    // cache tempfile. Fetch each distinct digest once, then unpack layers in manifest order
+    // below, which whiteout semantics require.
+    let mut unique_layers: Vec<(&str, &str)> = Vec::new();
+    for layer in &manifest.layers {
+        if !unique_layers.iter().any(|(digest, _)| *digest == layer.digest) {
+            unique_layers.push((&layer.digest, &layer.media_type));
+        }
+    }
+
+    let downloads: Vec<(&str, Result<PathBuf>)> = stream::iter(unique_layers)
+        .map(|(digest, media_type)| async move {
+            let path = fetch_blob_cached(client, image_ref, token, digest, media_type).await;
+            (digest, path)
+        })
+        .buffer_unordered(MAX_CONCURRENT_LAYER_DOWNLOADS)
+        .collect()
+        .await;
+
+    let mut layer_paths: HashMap<&str, PathBuf> = HashMap::new();
+    for (digest, path) in downloads {
+        layer_paths.insert(digest, path.with_context(|| format!("layer {digest} failed to download"))?);
+    }
+
+    for layer in &manifest.layers {
+        let path = layer_paths
+            .get(layer.digest.as_str())
+            .expect("every layer digest was populated by the download loop above");
+        apply_layer(path, CHROOT_DIR)
+            .with_context(|| format!("failed to unpack layer {}", &layer.digest))?;
+    }
+
+    Ok(config_blob.config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_image_defaults_to_docker_hub_and_latest() {
+        let image_ref = parse_image("ubuntu").unwrap();
+        assert_eq!(image_ref.registry, "registry-1.docker.io");
+        assert_eq!(image_ref.repository, "library/ubuntu");
+        assert_eq!(image_ref.reference, "latest");
+    }
+
+    #[test]
+    fn parse_image_with_tag() {
+        let image_ref = parse_image("ubuntu:22.04").unwrap();
+        assert_eq!(image_ref.registry, "registry-1.docker.io");
+        assert_eq!(image_ref.repository, "library/ubuntu");
+        assert_eq!(image_ref.reference, "22.04");
+    }
+
+    #[test]
+    fn parse_image_does_not_inject_library_for_namespaced_repos() {
+        let image_ref = parse_image("bitnami/nginx:latest").unwrap();
+        assert_eq!(image_ref.registry, "registry-1.docker.io");
+        assert_eq!(image_ref.repository, "bitnami/nginx");
+        assert_eq!(image_ref.reference, "latest");
+    }
+
+    #[test]
+    fn parse_image_with_digest() {
+        let image_ref =
+            parse_image("ubuntu@sha256:c9cf959fd83770dfdefd8fb42cfef0761432af36a764c077aed54bbc5bb2536")
+                .unwrap();
+        assert_eq!(image_ref.repository, "library/ubuntu");
+        assert_eq!(
+            image_ref.reference,
+            "sha256:c9cf959fd83770dfdefd8fb42cfef0761432af36a764c077aed54bbc5bb2536"
         );
-        let image_layer_response = client
-            .get(request)
-            .bearer_auth(token)
-            .header(reqwest::header::ACCEPT, &layer.media_type)
-            .send()
-            .await
-            .context("failed to download image layer")?
-            .bytes()
-            .await
-            .context("failed to get back bytes for layer")?;
+    }
 
-        // Use a cursor to write bytes to a temporary file, which we will then unpack to our chroot'ed directory
-        let mut bytes = Cursor::new(image_layer_response);
-        let mut file = tempfile::tempfile().context("failed to create tempfile")?;
-        std::io::copy(&mut bytes, &mut file).context("failed to copy layer bytes to temp file")?;
+    #[test]
+    fn parse_image_with_explicit_registry_host() {
+        let image_ref = parse_image("ghcr.io/owner/repo:v1").unwrap();
+        assert_eq!(image_ref.registry, "ghcr.io");
+        assert_eq!(image_ref.repository, "owner/repo");
+        assert_eq!(image_ref.reference, "v1");
+    }
 
-        file.seek(SeekFrom::Start(0))
-            .context("failed to start seeking at beginning of file")?;
-        let decoded = GzDecoder::new(file);
-        Archive::new(decoded)
-            .unpack(CHROOT_DIR)
-            .context("failed to unpack archive")?;
+    #[test]
+    fn parse_image_with_host_port_and_no_tag_is_not_mistaken_for_a_tag() {
+        let image_ref = parse_image("localhost:5000/repo").unwrap();
+        assert_eq!(image_ref.registry, "localhost:5000");
+        assert_eq!(image_ref.repository, "repo");
+        assert_eq!(image_ref.reference, "latest");
     }
 
-    Ok(())
+    #[test]
+    fn parse_image_with_host_port_and_tag() {
+        let image_ref = parse_image("localhost:5000/repo:v2").unwrap();
+        assert_eq!(image_ref.registry, "localhost:5000");
+        assert_eq!(image_ref.repository, "repo");
+        assert_eq!(image_ref.reference, "v2");
+    }
+
+    #[test]
+    fn parse_bearer_challenge_parses_all_fields() {
+        let challenge = parse_bearer_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/ubuntu:pull""#,
+        )
+        .unwrap();
+        assert_eq!(challenge.realm, "https://auth.docker.io/token");
+        assert_eq!(challenge.service.as_deref(), Some("registry.docker.io"));
+        assert_eq!(
+            challenge.scope.as_deref(),
+            Some("repository:library/ubuntu:pull")
+        );
+    }
+
+    #[test]
+    fn parse_bearer_challenge_service_and_scope_are_optional() {
+        let challenge =
+            parse_bearer_challenge(r#"Bearer realm="https://auth.example.com/token""#).unwrap();
+        assert_eq!(challenge.realm, "https://auth.example.com/token");
+        assert_eq!(challenge.service, None);
+        assert_eq!(challenge.scope, None);
+    }
+
+    #[test]
+    fn parse_bearer_challenge_rejects_non_bearer_schemes() {
+        assert!(parse_bearer_challenge(r#"Basic realm="example""#).is_err());
+    }
+
+    #[test]
+    fn parse_bearer_challenge_rejects_missing_realm() {
+        assert!(parse_bearer_challenge(r#"Bearer service="registry.docker.io""#).is_err());
+    }
+
+    fn container_config(
+        entrypoint: Option<Vec<&str>>,
+        cmd: Option<Vec<&str>>,
+    ) -> ContainerConfig {
+        ContainerConfig {
+            env: None,
+            entrypoint: entrypoint.map(|args| args.into_iter().map(String::from).collect()),
+            cmd: cmd.map(|args| args.into_iter().map(String::from).collect()),
+            working_dir: None,
+        }
+    }
+
+    #[test]
+    fn resolve_argv_uses_default_cmd_when_no_user_args() {
+        let config = container_config(Some(vec!["/entrypoint.sh"]), Some(vec!["serve"]));
+        assert_eq!(
+            resolve_argv(&config, &[]),
+            vec!["/entrypoint.sh".to_string(), "serve".to_string()]
+        );
+    }
+
+    #[test]
+    fn resolve_argv_user_args_override_cmd_but_keep_entrypoint() {
+        let config = container_config(Some(vec!["/entrypoint.sh"]), Some(vec!["serve"]));
+        let user_args = vec!["migrate".to_string()];
+        assert_eq!(
+            resolve_argv(&config, &user_args),
+            vec!["/entrypoint.sh".to_string(), "migrate".to_string()]
+        );
+    }
+
+    #[test]
+    fn resolve_argv_with_no_entrypoint_just_runs_cmd_or_user_args() {
+        let config = container_config(None, Some(vec!["serve"]));
+        assert_eq!(resolve_argv(&config, &[]), vec!["serve".to_string()]);
+
+        let user_args = vec!["bash".to_string()];
+        assert_eq!(resolve_argv(&config, &user_args), vec!["bash".to_string()]);
+    }
+
+    #[test]
+    fn resolve_argv_with_neither_entrypoint_nor_cmd_is_empty() {
+        let config = container_config(None, None);
+        assert!(resolve_argv(&config, &[]).is_empty());
+    }
+
+    #[test]
+    fn verify_digest_hex_accepts_matching_digest() {
+        let hex = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert!(verify_digest_hex(hex, &format!("sha256:{hex}")).is_ok());
+    }
+
+    #[test]
+    fn verify_digest_hex_rejects_mismatch() {
+        let hex = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert!(verify_digest_hex(hex, "sha256:0000000000000000000000000000000000000000000000000000000000000000").is_err());
+    }
+
+    #[test]
+    fn verify_digest_hex_rejects_unsupported_algorithm() {
+        let hex = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert!(verify_digest_hex(hex, &format!("sha512:{hex}")).is_err());
+    }
+
+    #[test]
+    fn verify_digest_hex_rejects_malformed_digest() {
+        assert!(verify_digest_hex("deadbeef", "not-a-digest").is_err());
+    }
 }